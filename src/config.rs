@@ -1,7 +1,178 @@
 use std::marker::PhantomData;
+use std::sync::Arc;
+use std::time::Duration;
 
+use sqlx::error::DatabaseError;
+
+use crate::extension::TxSource;
 use crate::Layer;
 
+/// The SQLSTATE codes considered transient enough to retry the whole request for.
+///
+/// `40001` is `serialization_failure` and `40P01` is `deadlock_detected` (both Postgres; other
+/// backends that surface equivalent codes benefit from the same retry).
+const RETRYABLE_SQLSTATES: [&str; 2] = ["40001", "40P01"];
+
+pub(crate) fn is_retryable(error: &sqlx::Error) -> bool {
+    error
+        .as_database_error()
+        .and_then(|error| error.code())
+        .is_some_and(|code| RETRYABLE_SQLSTATES.contains(&code.as_ref()))
+}
+
+/// A policy for automatically retrying the whole request when the final `COMMIT` fails with a
+/// serialization failure or deadlock.
+///
+/// See [`Config::retry_on_conflict`].
+#[derive(Debug, Clone)]
+pub struct RetryPolicy {
+    pub(crate) max_attempts: u32,
+    pub(crate) base_delay: Duration,
+    pub(crate) max_delay: Duration,
+    pub(crate) jitter: bool,
+    pub(crate) body_limit: usize,
+}
+
+impl RetryPolicy {
+    /// Retry up to `max_attempts` times, starting at a 10ms base delay doubling up to a 1s cap,
+    /// with jitter enabled and a 1MiB request body buffer limit.
+    pub fn new(max_attempts: u32) -> Self {
+        Self {
+            max_attempts,
+            base_delay: Duration::from_millis(10),
+            max_delay: Duration::from_secs(1),
+            jitter: true,
+            body_limit: 1024 * 1024,
+        }
+    }
+
+    /// The delay before the first retry; doubles on each subsequent attempt.
+    pub fn base_delay(mut self, base_delay: Duration) -> Self {
+        self.base_delay = base_delay;
+        self
+    }
+
+    /// The cap on the exponential backoff, regardless of attempt count.
+    pub fn max_delay(mut self, max_delay: Duration) -> Self {
+        self.max_delay = max_delay;
+        self
+    }
+
+    /// Whether to jitter the backoff delay to avoid retry storms.
+    pub fn jitter(mut self, jitter: bool) -> Self {
+        self.jitter = jitter;
+        self
+    }
+
+    /// The maximum request body size (in bytes) that will be buffered to allow a retry.
+    ///
+    /// Requests whose body exceeds this limit (whether declared via `Content-Length` up front, or
+    /// discovered only once read) are still served, just without the ability to retry.
+    pub fn body_limit(mut self, body_limit: usize) -> Self {
+        self.body_limit = body_limit;
+        self
+    }
+
+    /// The backoff delay before retry number `attempt` (1-based).
+    ///
+    /// `salt` is a per-request value (e.g. a call counter) mixed into the jitter so that two
+    /// concurrently-retrying requests failing at the same `attempt` don't compute the same delay.
+    pub(crate) fn delay(&self, attempt: u32, salt: u64) -> Duration {
+        let factor = 1u32
+            .checked_shl(attempt.saturating_sub(1))
+            .unwrap_or(u32::MAX);
+        let delay = self.base_delay.saturating_mul(factor).min(self.max_delay);
+
+        if self.jitter {
+            // Cheap deterministic jitter (no RNG dependency): spread delays over roughly the last
+            // 25% of the window so concurrent retries don't all wake up at once.
+            let salted = (u64::from(attempt) * 37) ^ salt.wrapping_mul(2654435761);
+            let jitter_ms = salted % (delay.as_millis() as u64 / 4 + 1);
+            delay - Duration::from_millis(jitter_ms)
+        } else {
+            delay
+        }
+    }
+}
+
+/// A predicate deciding whether the per-request transaction should be committed, given the
+/// response head that's about to be sent.
+///
+/// See [`Config::commit_when`].
+pub type CommitWhen = Arc<dyn Fn(&http::Response<()>) -> bool + Send + Sync>;
+
+/// The default policy: commit unless the response is a 4xx or 5xx.
+pub(crate) fn default_commit_when() -> CommitWhen {
+    Arc::new(|res| !res.status().is_client_error() && !res.status().is_server_error())
+}
+
+/// The isolation level of a per-request transaction.
+///
+/// See [`Config::isolation_level`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IsolationLevel {
+    ReadUncommitted,
+    ReadCommitted,
+    RepeatableRead,
+    Serializable,
+}
+
+impl IsolationLevel {
+    pub(crate) fn as_sql(self) -> &'static str {
+        match self {
+            Self::ReadUncommitted => "READ UNCOMMITTED",
+            Self::ReadCommitted => "READ COMMITTED",
+            Self::RepeatableRead => "REPEATABLE READ",
+            Self::Serializable => "SERIALIZABLE",
+        }
+    }
+}
+
+/// How the per-request transaction should be opened, beyond the pool's defaults.
+///
+/// Threaded from [`Config`] down to [`LazyTransaction`](crate::extension::LazyTransaction), which
+/// issues the corresponding `SET TRANSACTION` statement (worded per `DB::NAME`) right after
+/// `BEGIN`.
+#[derive(Debug, Clone, Copy, Default)]
+pub(crate) struct TxOptions {
+    pub(crate) isolation_level: Option<IsolationLevel>,
+    pub(crate) read_only: bool,
+}
+
+impl TxOptions {
+    /// The `SET TRANSACTION ...` statement to run right after `BEGIN`, if anything was
+    /// configured, or an error if `DB` doesn't support one.
+    ///
+    /// Both PostgreSQL and MySQL separate multiple transaction modes with a comma (Postgres's
+    /// `SET TRANSACTION` grammar is `transaction_mode [, ...]`; a plain space between `ISOLATION
+    /// LEVEL ...` and `READ ONLY` is a syntax error there too). SQLite has no `SET TRANSACTION`
+    /// statement at all (isolation there is a pragma/locking-mode concern, not a per-transaction
+    /// one), so when [`Config::isolation_level`](crate::Config::isolation_level) or
+    /// [`Config::read_only`](crate::Config::read_only) is set this returns
+    /// [`Error::UnsupportedTxOptions`](crate::Error::UnsupportedTxOptions) for it (failing the
+    /// acquisition of the transaction) rather than sending SQL it can't parse.
+    pub(crate) fn statement<DB: sqlx::Database>(&self) -> Result<Option<String>, crate::Error> {
+        if self.isolation_level.is_none() && !self.read_only {
+            return Ok(None);
+        }
+
+        match DB::NAME {
+            "PostgreSQL" | "MySQL" => {}
+            backend => return Err(crate::Error::UnsupportedTxOptions(backend)),
+        }
+
+        let mut parts = Vec::new();
+        if let Some(level) = self.isolation_level {
+            parts.push(format!("ISOLATION LEVEL {}", level.as_sql()));
+        }
+        if self.read_only {
+            parts.push("READ ONLY".to_owned());
+        }
+
+        Ok(Some(format!("SET TRANSACTION {}", parts.join(", "))))
+    }
+}
+
 /// Configuration for [`Tx`](crate::Tx) extractors.
 ///
 /// Use `Config` to configure and create a [`State`] and [`Layer`].
@@ -18,6 +189,11 @@ use crate::Layer;
 /// ```
 pub struct Config<DB: sqlx::Database, LayerError> {
     pool: sqlx::Pool<DB>,
+    nested: bool,
+    commit_when: CommitWhen,
+    tx_options: TxOptions,
+    retry: Option<RetryPolicy>,
+    tx_source: Option<Arc<dyn TxSource<DB>>>,
     _layer_error: PhantomData<LayerError>,
 }
 
@@ -33,13 +209,104 @@ where
     {
         Config {
             pool: self.pool,
+            nested: self.nested,
+            commit_when: self.commit_when,
+            tx_options: self.tx_options,
+            retry: self.retry,
+            tx_source: self.tx_source,
             _layer_error: PhantomData,
         }
     }
 
+    /// Allow the [`Tx`](crate::Tx) extractor to be acquired while another scope is already live,
+    /// by opening a nested `SAVEPOINT` scope instead of failing with
+    /// [`Error::OverlappingExtractors`](crate::Error::OverlappingExtractors).
+    ///
+    /// This is off by default: a second, concurrently-live `Tx` scope (e.g. one branch of a
+    /// `tokio::join!`, or a helper called while the caller's own `Tx` is still in scope) usually
+    /// indicates a bug. Enable it when that pattern is intentional, so each nested scope can be
+    /// released or rolled back independently of its parent.
+    pub fn nested_transactions(mut self) -> Self {
+        self.nested = true;
+        self
+    }
+
+    /// Replace the policy deciding whether the per-request transaction is committed.
+    ///
+    /// By default the transaction is committed unless the response is a 4xx or 5xx. Supply a
+    /// predicate to roll back on custom headers, commit on 4xx responses used for idempotent
+    /// conflict signalling, or always roll back in a dry-run mode.
+    ///
+    /// ```
+    /// # async fn foo() {
+    /// # let pool: sqlx::SqlitePool = todo!();
+    /// type Tx = axum_sqlx_tx::Tx<sqlx::Sqlite>;
+    ///
+    /// let config = Tx::config(pool).commit_when(|res| !res.status().is_server_error());
+    /// # }
+    /// ```
+    pub fn commit_when<F>(mut self, commit_when: F) -> Self
+    where
+        F: Fn(&http::Response<()>) -> bool + Send + Sync + 'static,
+    {
+        self.commit_when = Arc::new(commit_when);
+        self
+    }
+
+    /// Open the per-request transaction at the given isolation level, via `SET TRANSACTION
+    /// ISOLATION LEVEL ...` issued right after `BEGIN`.
+    ///
+    /// Useful for running read-heavy routes at a weaker isolation level than the pool default, or
+    /// opting specific routes into `SERIALIZABLE`. Not supported on SQLite, which has no `SET
+    /// TRANSACTION` statement; acquiring a [`Tx`](crate::Tx) against it fails with
+    /// [`Error::UnsupportedTxOptions`](crate::Error::UnsupportedTxOptions) instead.
+    pub fn isolation_level(mut self, level: IsolationLevel) -> Self {
+        self.tx_options.isolation_level = Some(level);
+        self
+    }
+
+    /// Open the per-request transaction in (or out of) read-only mode, via `SET TRANSACTION READ
+    /// ONLY` issued right after `BEGIN`.
+    ///
+    /// Not supported on SQLite; see [`isolation_level`](Self::isolation_level).
+    pub fn read_only(mut self, read_only: bool) -> Self {
+        self.tx_options.read_only = read_only;
+        self
+    }
+
+    /// Automatically retry the whole request when the final `COMMIT` fails with a serialization
+    /// failure or deadlock (SQLSTATE `40001`/`40P01`), which can happen at the `Serializable` or
+    /// `RepeatableRead` [isolation level](Self::isolation_level).
+    ///
+    /// See [`RetryPolicy::body_limit`] for how (and how much of) the request body is buffered to
+    /// allow a retry.
+    pub fn retry_on_conflict(mut self, policy: RetryPolicy) -> Self {
+        self.retry = Some(policy);
+        self
+    }
+
+    /// Override how the per-request transaction is started, instead of going through the
+    /// configured [`sqlx::Pool`] directly.
+    ///
+    /// Mainly useful in tests, to swap in a [`TxSource`] that runs against an in-memory or
+    /// recording stand-in rather than a real database connection.
+    pub fn tx_source(mut self, source: impl TxSource<DB> + 'static) -> Self {
+        self.tx_source = Some(Arc::new(source));
+        self
+    }
+
     /// Create a [`State`] and [`Layer`] to enable the [`Tx`](crate::Tx) extractor.
     pub fn setup(self) -> (sqlx::Pool<DB>, Layer<DB, LayerError>) {
-        let layer = Layer::from(self.pool.clone());
+        let source = self
+            .tx_source
+            .unwrap_or_else(|| Arc::new(self.pool.clone()) as Arc<dyn TxSource<DB>>);
+        let layer = Layer::new(
+            source,
+            self.nested,
+            self.commit_when,
+            self.tx_options,
+            self.retry,
+        );
         (self.pool, layer)
     }
 }
@@ -52,7 +319,85 @@ where
     fn from(value: sqlx::Pool<DB>) -> Self {
         Self {
             pool: value,
+            nested: false,
+            commit_when: default_commit_when(),
+            tx_options: TxOptions::default(),
+            retry: None,
+            tx_source: None,
             _layer_error: PhantomData,
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn statement_none_when_unconfigured() {
+        assert_eq!(
+            TxOptions::default().statement::<sqlx::Postgres>().unwrap(),
+            None
+        );
+    }
+
+    #[test]
+    fn statement_postgres_isolation_level_only() {
+        let options = TxOptions {
+            isolation_level: Some(IsolationLevel::Serializable),
+            read_only: false,
+        };
+        assert_eq!(
+            options.statement::<sqlx::Postgres>().unwrap().as_deref(),
+            Some("SET TRANSACTION ISOLATION LEVEL SERIALIZABLE")
+        );
+    }
+
+    #[test]
+    fn statement_postgres_read_only_only() {
+        let options = TxOptions {
+            isolation_level: None,
+            read_only: true,
+        };
+        assert_eq!(
+            options.statement::<sqlx::Postgres>().unwrap().as_deref(),
+            Some("SET TRANSACTION READ ONLY")
+        );
+    }
+
+    #[test]
+    fn statement_postgres_isolation_level_and_read_only_are_comma_separated() {
+        let options = TxOptions {
+            isolation_level: Some(IsolationLevel::Serializable),
+            read_only: true,
+        };
+        assert_eq!(
+            options.statement::<sqlx::Postgres>().unwrap().as_deref(),
+            Some("SET TRANSACTION ISOLATION LEVEL SERIALIZABLE, READ ONLY")
+        );
+    }
+
+    #[test]
+    fn statement_mysql_isolation_level_and_read_only_are_comma_separated() {
+        let options = TxOptions {
+            isolation_level: Some(IsolationLevel::RepeatableRead),
+            read_only: true,
+        };
+        assert_eq!(
+            options.statement::<sqlx::MySql>().unwrap().as_deref(),
+            Some("SET TRANSACTION ISOLATION LEVEL REPEATABLE READ, READ ONLY")
+        );
+    }
+
+    #[test]
+    fn statement_errors_on_unsupported_backend() {
+        let options = TxOptions {
+            isolation_level: Some(IsolationLevel::Serializable),
+            read_only: false,
+        };
+        assert!(matches!(
+            options.statement::<sqlx::Sqlite>(),
+            Err(crate::Error::UnsupportedTxOptions("SQLite"))
+        ));
+    }
+}
@@ -1,103 +1,509 @@
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::Arc;
 
+use futures_core::future::BoxFuture;
 use parking_lot::{lock_api::ArcMutexGuard, Mutex, RawMutex};
-use sqlx::Transaction;
+use sqlx::{Executor, Transaction};
 
+use crate::config::TxOptions;
 use crate::Error;
 
+/// A callback registered via [`TxGuard::after_commit`], run once the outermost transaction has
+/// durably committed.
+pub(crate) type AfterCommitHook = Box<dyn FnOnce() -> BoxFuture<'static, ()> + Send>;
+
+/// A source of per-request transactions, decoupling [`Extension`]/[`LazyTransaction`] from a
+/// concrete [`sqlx::Pool`].
+///
+/// [`sqlx::Pool`] is the only implementation most users need (see the blanket impl below).
+/// Supply your own via [`Config::tx_source`](crate::Config::tx_source) to exercise
+/// [`Tx`](crate::Tx) against a stand-in backend instead of a real database, e.g. in tests.
+pub trait TxSource<DB: sqlx::Database>: Send + Sync {
+    fn begin(&self) -> BoxFuture<'_, Result<Transaction<'static, DB>, sqlx::Error>>;
+}
+
+impl<DB: sqlx::Database> TxSource<DB> for sqlx::Pool<DB> {
+    fn begin(&self) -> BoxFuture<'_, Result<Transaction<'static, DB>, sqlx::Error>> {
+        Box::pin(sqlx::Pool::begin(self))
+    }
+}
+
+/// A [`TxSource`] backed by an in-memory SQLite pool, for exercising [`Tx`](crate::Tx) in tests
+/// (your own, or downstream users') without standing up a real database.
+///
+/// Statements still run against genuine (if ephemeral) SQLite rather than canned results: faking
+/// the executor surface itself would mean abstracting over [`sqlx::Transaction`] as well as
+/// `begin`, which this trait deliberately doesn't attempt. Plug it in via
+/// [`Config::tx_source`](crate::Config::tx_source).
+pub struct InMemoryTxSource(sqlx::SqlitePool);
+
+impl InMemoryTxSource {
+    pub async fn connect() -> Result<Self, sqlx::Error> {
+        Ok(Self(sqlx::SqlitePool::connect("sqlite::memory:").await?))
+    }
+}
+
+impl TxSource<sqlx::Sqlite> for InMemoryTxSource {
+    fn begin(&self) -> BoxFuture<'_, Result<Transaction<'static, sqlx::Sqlite>, sqlx::Error>> {
+        Box::pin(sqlx::Pool::begin(&self.0))
+    }
+}
+
+/// A [`TxSource`] wrapper that counts how many transactions have been started through it, useful
+/// for asserting "the handler opened exactly one transaction" in tests (your own, or downstream
+/// users').
+///
+/// This only records (and could only swap out) the `begin` step itself; queries run against
+/// whatever real transaction the wrapped source hands back. Plug it in via
+/// [`Config::tx_source`](crate::Config::tx_source).
+pub struct RecordingTxSource<DB: sqlx::Database> {
+    inner: Arc<dyn TxSource<DB>>,
+    begins: std::sync::atomic::AtomicUsize,
+}
+
+impl<DB: sqlx::Database> RecordingTxSource<DB> {
+    pub fn new(inner: Arc<dyn TxSource<DB>>) -> Self {
+        Self {
+            inner,
+            begins: std::sync::atomic::AtomicUsize::new(0),
+        }
+    }
+
+    pub fn begin_count(&self) -> usize {
+        self.begins.load(std::sync::atomic::Ordering::Relaxed)
+    }
+}
+
+impl<DB: sqlx::Database> TxSource<DB> for RecordingTxSource<DB> {
+    fn begin(&self) -> BoxFuture<'_, Result<Transaction<'static, DB>, sqlx::Error>> {
+        self.begins.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+        self.inner.begin()
+    }
+}
+
+/// How many times [`Extension::acquire`] cooperatively yields while waiting for a sibling scope
+/// (e.g. one branch of a `tokio::join!`) to release the transaction, before giving up with
+/// [`Error::OverlappingExtractors`].
+const NESTED_ACQUIRE_ATTEMPTS: usize = 1024;
+
 /// The request extension.
-pub(crate) struct Extension<DB: sqlx::Database>(Arc<Mutex<LazyTransaction<DB>>>);
+pub(crate) struct Extension<DB: sqlx::Database> {
+    tx: Arc<Mutex<LazyTransaction<DB>>>,
+    nested: bool,
+    /// Set while a [`TxGuard`] derived from this extension is [`suspend`](TxGuard::suspend)ed, so
+    /// a re-entrant [`acquire`](Self::acquire) that finds the lock free (because its ancestor just
+    /// released it, not because no one's holding a scope) still opens its own `SAVEPOINT` instead
+    /// of silently reusing the ancestor's depth.
+    suspended: Arc<AtomicBool>,
+}
 
 impl<DB: sqlx::Database> Extension<DB> {
-    pub(crate) async fn acquire(
-        &self,
-    ) -> Result<ArcMutexGuard<RawMutex, LazyTransaction<DB>>, Error> {
-        let mut tx = self.0.try_lock_arc().ok_or(Error::OverlappingExtractors)?;
-        tx.acquire().await?;
+    pub(crate) fn new(pool: sqlx::Pool<DB>, nested: bool, tx_options: TxOptions) -> Self {
+        Self::with_source(Arc::new(pool), nested, tx_options)
+    }
+
+    /// As [`new`](Self::new), but taking an arbitrary [`TxSource`] rather than a concrete
+    /// [`sqlx::Pool`] (e.g. [`InMemoryTxSource`] in tests).
+    pub(crate) fn with_source(
+        source: Arc<dyn TxSource<DB>>,
+        nested: bool,
+        tx_options: TxOptions,
+    ) -> Self {
+        Self {
+            tx: Arc::new(Mutex::new(LazyTransaction::new(source, tx_options))),
+            nested,
+            suspended: Arc::new(AtomicBool::new(false)),
+        }
+    }
+
+    pub(crate) async fn acquire(&self) -> Result<TxGuard<DB>, Error> {
+        let suspended = self.suspended.load(Ordering::Acquire);
+        let (mut guard, savepoint) = match self.tx.try_lock_arc() {
+            Some(guard) if !suspended => (guard, false),
+            Some(guard) if self.nested => (guard, true),
+            Some(_) => return Err(Error::OverlappingExtractors),
+            None if self.nested => (self.wait_for_scope().await?, true),
+            None => return Err(Error::OverlappingExtractors),
+        };
+
+        let depth = guard.acquire(savepoint).await?;
 
-        Ok(tx)
+        Ok(TxGuard {
+            tx: self.tx.clone(),
+            suspended: self.suspended.clone(),
+            guard: Some(guard),
+            depth,
+        })
+    }
+
+    /// Cooperatively wait for the transaction to become available, so the caller can open a
+    /// nested `SAVEPOINT` scope instead of erroring with [`Error::OverlappingExtractors`].
+    ///
+    /// This makes progress against a *sibling* scope that is concurrently polled on the same task
+    /// (e.g. a `tokio::join!`ed helper) and will itself release the lock, and against a scope
+    /// further up the same call stack that has been cooperatively suspended (via
+    /// [`TxGuard::suspend`]) by its holder for the duration of a call into code that needs its own
+    /// `Tx`. It cannot help a scope still held (not suspended) further up the same call stack.
+    async fn wait_for_scope(&self) -> Result<ArcMutexGuard<RawMutex, LazyTransaction<DB>>, Error> {
+        for _ in 0..NESTED_ACQUIRE_ATTEMPTS {
+            if let Some(tx) = self.tx.try_lock_arc() {
+                return Ok(tx);
+            }
+            tokio::task::yield_now().await;
+        }
+        Err(Error::OverlappingExtractors)
     }
 
     pub(crate) async fn resolve(&self) -> Result<(), sqlx::Error> {
-        if let Some(mut tx) = self.0.try_lock_arc() {
-            tx.resolve().await?;
+        if let Some(mut tx) = self.tx.try_lock_arc() {
+            let hooks = tx.resolve().await?;
+            drop(tx); // don't hold the lock while running caller-supplied hooks
+            for hook in hooks {
+                hook().await;
+            }
         }
         Ok(())
     }
+
+    /// Discard the transaction without committing, e.g. because
+    /// [`Config::commit_when`](crate::Config::commit_when) rejected the response.
+    pub(crate) async fn rollback(&self) {
+        if let Some(mut tx) = self.tx.try_lock_arc() {
+            tx.rollback();
+        }
+    }
 }
 
 impl<DB: sqlx::Database> From<sqlx::Pool<DB>> for Extension<DB> {
     fn from(value: sqlx::Pool<DB>) -> Self {
-        Self(Arc::new(Mutex::new(LazyTransaction::new(value))))
+        Self::new(value, false, TxOptions::default())
     }
 }
 
 impl<DB: sqlx::Database> Clone for Extension<DB> {
     fn clone(&self) -> Self {
-        Self(self.0.clone())
+        Self {
+            tx: self.tx.clone(),
+            nested: self.nested,
+            suspended: self.suspended.clone(),
+        }
+    }
+}
+
+/// A guard over an acquired transaction scope, which may be the outermost transaction or a
+/// nested `SAVEPOINT` scope opened on top of it.
+///
+/// The outermost scope (`depth == 0`) is resolved as before: [`Extension::resolve`] drives the
+/// final `COMMIT`/`ROLLBACK` from [`Service::call`](crate::layer::Service::call). A nested scope
+/// (`depth > 0`) must instead be resolved explicitly with [`release`](Self::release) or
+/// [`rollback`](Self::rollback), since ending a `SAVEPOINT` requires an `await`able query that a
+/// `Drop` impl cannot run.
+///
+/// The lock on the underlying transaction is held via an `Option` rather than unconditionally, so
+/// a holder can [`suspend`](Self::suspend) it before calling into code that acquires its own
+/// `Tx` for the same request (e.g. a helper function taking `Tx<DB>` as a parameter) and
+/// [`resume`](Self::resume) it afterwards, rather than deadlocking against itself.
+pub(crate) struct TxGuard<DB: sqlx::Database> {
+    tx: Arc<Mutex<LazyTransaction<DB>>>,
+    /// Shared with the [`Extension`] this guard came from (and any of its clones), so a re-entrant
+    /// [`Extension::acquire`] can tell "lock free because suspended" apart from "lock free because
+    /// nobody's holding a scope".
+    suspended: Arc<AtomicBool>,
+    guard: Option<ArcMutexGuard<RawMutex, LazyTransaction<DB>>>,
+    depth: u32,
+}
+
+impl<DB: sqlx::Database> TxGuard<DB> {
+    pub(crate) fn as_ref(&self) -> &Transaction<'static, DB> {
+        self.held().as_ref()
+    }
+
+    pub(crate) fn as_mut(&mut self) -> &mut Transaction<'static, DB> {
+        self.held_mut().as_mut()
+    }
+
+    /// Release the lock on the underlying transaction without resolving this scope, so a nested
+    /// call that needs its own `Tx` for this request (rather than a concurrently-polled sibling)
+    /// can acquire it via [`Extension::acquire`], which then opens a nested `SAVEPOINT` scope
+    /// (with nested transactions enabled; otherwise it fails with
+    /// [`Error::OverlappingExtractors`]).
+    ///
+    /// The caller is responsible for calling [`resume`](Self::resume) before using this guard
+    /// again; every method that touches the transaction panics while suspended.
+    pub(crate) fn suspend(&mut self) {
+        self.suspended.store(true, Ordering::Release);
+        self.guard = None;
+    }
+
+    /// Re-acquire the lock released by [`suspend`](Self::suspend), cooperatively yielding while
+    /// any nested scope that is still using it finishes. A no-op if not currently suspended.
+    pub(crate) async fn resume(&mut self) -> Result<(), Error> {
+        if self.guard.is_some() {
+            return Ok(());
+        }
+        for _ in 0..NESTED_ACQUIRE_ATTEMPTS {
+            if let Some(guard) = self.tx.try_lock_arc() {
+                self.guard = Some(guard);
+                self.suspended.store(false, Ordering::Release);
+                return Ok(());
+            }
+            tokio::task::yield_now().await;
+        }
+        Err(Error::OverlappingExtractors)
+    }
+
+    /// Whether this guard is a nested `SAVEPOINT` scope rather than the outermost transaction.
+    pub(crate) fn is_nested(&self) -> bool {
+        self.depth > 0
+    }
+
+    /// Register a callback to run only once the request's transaction has durably committed,
+    /// e.g. to send a `NOTIFY` or enqueue a background job. Dropped silently on rollback.
+    ///
+    /// Tagged with this scope's nesting depth: a nested `SAVEPOINT` scope can still register a
+    /// hook here, and it only fires once the *outermost* transaction commits (since `RELEASE
+    /// SAVEPOINT` doesn't make a scope's changes durable on its own), but only if this scope (and
+    /// every scope nested inside it) was actually released rather than rolled back — see
+    /// [`rollback`](Self::rollback).
+    pub(crate) fn after_commit<F>(&mut self, hook: F)
+    where
+        F: FnOnce() -> BoxFuture<'static, ()> + Send + 'static,
+    {
+        let depth = self.depth;
+        self.held_mut().push_after_commit(depth, Box::new(hook));
+    }
+
+    /// Release this scope's `SAVEPOINT`, keeping its changes visible to the parent scope.
+    ///
+    /// A no-op for the outermost scope, which is resolved by [`Extension::resolve`] instead.
+    pub(crate) async fn release(mut self) -> Result<(), sqlx::Error> {
+        if self.depth > 0 {
+            self.held_mut()
+                .as_mut()
+                .execute(format!("RELEASE SAVEPOINT axum_sqlx_tx_{}", self.depth).as_str())
+                .await?;
+        }
+        Ok(())
+    }
+
+    /// Roll back this scope's `SAVEPOINT`, discarding its changes while leaving the parent scope
+    /// free to continue.
+    ///
+    /// A no-op for the outermost scope, which is resolved by [`Extension::resolve`] instead. Also
+    /// discards any [`after_commit`](Self::after_commit) hooks registered at this depth or
+    /// deeper, since rolling back a `SAVEPOINT` means the side effects they were guarding never
+    /// actually happened, even though the outermost transaction may still go on to commit.
+    pub(crate) async fn rollback(mut self) -> Result<(), sqlx::Error> {
+        if self.depth > 0 {
+            self.held_mut()
+                .as_mut()
+                .execute(format!("ROLLBACK TO SAVEPOINT axum_sqlx_tx_{}", self.depth).as_str())
+                .await?;
+            let depth = self.depth;
+            self.held_mut().discard_after_commit_from(depth);
+        }
+        Ok(())
+    }
+
+    /// The held lock, panicking if this guard is currently [`suspend`](Self::suspend)ed.
+    fn held(&self) -> &LazyTransaction<DB> {
+        self.guard
+            .as_deref()
+            .expect("BUG: TxGuard used while suspended")
+    }
+
+    /// As [`held`](Self::held), but mutable.
+    fn held_mut(&mut self) -> &mut LazyTransaction<DB> {
+        self.guard
+            .as_deref_mut()
+            .expect("BUG: TxGuard used while suspended")
     }
 }
 
 /// The lazy transaction.
-pub(crate) struct LazyTransaction<DB: sqlx::Database>(LazyTransactionState<DB>);
+pub(crate) struct LazyTransaction<DB: sqlx::Database> {
+    state: LazyTransactionState<DB>,
+    /// Hooks queued via [`push_after_commit`](Self::push_after_commit), tagged with the nesting
+    /// depth of the scope that registered them so a `SAVEPOINT` rollback can discard only the
+    /// hooks belonging to the scope(s) it unwound.
+    after_commit: Vec<(u32, AfterCommitHook)>,
+}
 
 enum LazyTransactionState<DB: sqlx::Database> {
-    Unacquired(sqlx::Pool<DB>),
-    Acquired(Transaction<'static, DB>),
+    Unacquired(Arc<dyn TxSource<DB>>, TxOptions),
+    /// An open transaction and the depth given to the most recently opened `SAVEPOINT` scope on
+    /// it (`0` if none has been opened). Releasing or rolling back a scope does not decrement it,
+    /// so each scope's depth (and savepoint name) is unique within the transaction.
+    Acquired(Transaction<'static, DB>, u32),
     Resolved,
 }
 
 impl<DB: sqlx::Database> LazyTransaction<DB> {
-    fn new(pool: sqlx::Pool<DB>) -> Self {
-        Self(LazyTransactionState::Unacquired(pool))
+    fn new(source: Arc<dyn TxSource<DB>>, tx_options: TxOptions) -> Self {
+        Self {
+            state: LazyTransactionState::Unacquired(source, tx_options),
+            after_commit: Vec::new(),
+        }
     }
 
     pub(crate) fn as_ref(&self) -> &Transaction<'static, DB> {
-        match &self.0 {
+        match &self.state {
             LazyTransactionState::Unacquired { .. } => {
                 panic!("BUG: exposed unacquired LazyTransaction")
             }
-            LazyTransactionState::Acquired(tx) => tx,
+            LazyTransactionState::Acquired(tx, _) => tx,
             LazyTransactionState::Resolved => panic!("BUG: exposed resolved LazyTransaction"),
         }
     }
 
     pub(crate) fn as_mut(&mut self) -> &mut Transaction<'static, DB> {
-        match &mut self.0 {
+        match &mut self.state {
             LazyTransactionState::Unacquired { .. } => {
                 panic!("BUG: exposed unacquired LazyTransaction")
             }
-            LazyTransactionState::Acquired(tx) => tx,
+            LazyTransactionState::Acquired(tx, _) => tx,
             LazyTransactionState::Resolved => panic!("BUG: exposed resolved LazyTransaction"),
         }
     }
 
-    async fn acquire(&mut self) -> Result<(), Error> {
-        match &self.0 {
-            LazyTransactionState::Unacquired(pool) => {
-                let tx = pool.begin().await?;
-                self.0 = LazyTransactionState::Acquired(tx);
-                Ok(())
+    /// Acquire the transaction, opening it if necessary.
+    ///
+    /// If `savepoint` is set and the transaction is already open, a new nested scope is opened
+    /// one level deeper via `SAVEPOINT` instead of simply reusing the current scope. Returns the
+    /// depth of the resulting scope (`0` for the outermost transaction).
+    async fn acquire(&mut self, savepoint: bool) -> Result<u32, Error> {
+        match &mut self.state {
+            LazyTransactionState::Unacquired(..) => {
+                let LazyTransactionState::Unacquired(source, tx_options) =
+                    std::mem::replace(&mut self.state, LazyTransactionState::Resolved)
+                else {
+                    unreachable!()
+                };
+                let mut tx = source.begin().await?;
+                if let Some(statement) = tx_options.statement::<DB>()? {
+                    tx.execute(statement.as_str()).await?;
+                }
+                self.state = LazyTransactionState::Acquired(tx, 0);
+                Ok(0)
+            }
+            LazyTransactionState::Acquired(tx, depth) => {
+                if savepoint {
+                    let next = *depth + 1;
+                    tx.execute(format!("SAVEPOINT axum_sqlx_tx_{next}").as_str())
+                        .await?;
+                    *depth = next;
+                    Ok(next)
+                } else {
+                    Ok(0)
+                }
             }
-            LazyTransactionState::Acquired { .. } => Ok(()),
             LazyTransactionState::Resolved => Err(Error::OverlappingExtractors),
         }
     }
 
-    pub(crate) async fn resolve(&mut self) -> Result<(), sqlx::Error> {
-        match std::mem::replace(&mut self.0, LazyTransactionState::Resolved) {
-            LazyTransactionState::Unacquired { .. } | LazyTransactionState::Resolved => Ok(()),
-            LazyTransactionState::Acquired(tx) => tx.commit().await,
+    /// Register a callback, tagged with the nesting depth of the scope registering it, to run
+    /// only once the outermost transaction durably commits.
+    pub(crate) fn push_after_commit(&mut self, depth: u32, hook: AfterCommitHook) {
+        self.after_commit.push((depth, hook));
+    }
+
+    /// Discard any queued hooks tagged with nesting depth `depth` or deeper, e.g. because the
+    /// `SAVEPOINT` scope at that depth was rolled back rather than released.
+    pub(crate) fn discard_after_commit_from(&mut self, depth: u32) {
+        self.after_commit.retain(|(hook_depth, _)| *hook_depth < depth);
+    }
+
+    /// Commit the transaction, returning the hooks queued via [`push_after_commit`] for the
+    /// caller to run once it's no longer holding the lock on `self`.
+    ///
+    /// [`push_after_commit`]: Self::push_after_commit
+    pub(crate) async fn resolve(&mut self) -> Result<Vec<AfterCommitHook>, sqlx::Error> {
+        match std::mem::replace(&mut self.state, LazyTransactionState::Resolved) {
+            LazyTransactionState::Unacquired { .. } | LazyTransactionState::Resolved => {
+                Ok(Vec::new())
+            }
+            LazyTransactionState::Acquired(tx, _) => {
+                tx.commit().await?;
+                let hooks = std::mem::take(&mut self.after_commit)
+                    .into_iter()
+                    .map(|(_, hook)| hook)
+                    .collect();
+                Ok(hooks)
+            }
         }
     }
 
+    /// Discard the transaction without committing, dropping any queued after-commit hooks.
+    /// Relies on [`sqlx::Transaction`]'s `Drop` impl to issue the actual `ROLLBACK`.
+    pub(crate) fn rollback(&mut self) {
+        self.state = LazyTransactionState::Resolved;
+        self.after_commit.clear();
+    }
+
     pub(crate) async fn commit(&mut self) -> Result<(), sqlx::Error> {
-        match std::mem::replace(&mut self.0, LazyTransactionState::Resolved) {
+        match std::mem::replace(&mut self.state, LazyTransactionState::Resolved) {
             LazyTransactionState::Unacquired { .. } => {
                 panic!("BUG: tried to commit unacquired transaction")
             }
-            LazyTransactionState::Acquired(tx) => tx.commit().await,
+            LazyTransactionState::Acquired(tx, _) => tx.commit().await,
             LazyTransactionState::Resolved => panic!("BUG: tried to commit resolved transaction"),
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn in_memory_tx_source_begins_and_runs_queries() {
+        let source = InMemoryTxSource::connect()
+            .await
+            .expect("in-memory sqlite connects");
+
+        let mut tx = source.begin().await.expect("begin succeeds");
+        tx.execute("CREATE TABLE t (id INTEGER PRIMARY KEY)")
+            .await
+            .expect("statement runs against the in-memory pool");
+        tx.commit().await.expect("commit succeeds");
+    }
+
+    #[tokio::test]
+    async fn recording_tx_source_counts_begins() {
+        let inner = InMemoryTxSource::connect()
+            .await
+            .expect("in-memory sqlite connects");
+        let recording = RecordingTxSource::new(Arc::new(inner));
+
+        recording.begin().await.expect("begin succeeds");
+        recording.begin().await.expect("begin succeeds");
+
+        assert_eq!(recording.begin_count(), 2);
+    }
+
+    #[tokio::test]
+    async fn reentrant_acquire_while_suspended_opens_its_own_savepoint() {
+        let source = InMemoryTxSource::connect()
+            .await
+            .expect("in-memory sqlite connects");
+        let ext = Extension::with_source(Arc::new(source), true, TxOptions::default());
+
+        let mut outer = ext.acquire().await.expect("outer acquire succeeds");
+        assert_eq!(outer.depth, 0);
+
+        outer.suspend();
+
+        let inner = ext
+            .acquire()
+            .await
+            .expect("reentrant acquire succeeds while the outer scope is suspended");
+        assert_eq!(
+            inner.depth, 1,
+            "a reentrant acquire must open its own SAVEPOINT rather than reuse the caller's depth"
+        );
+        inner.release().await.expect("release succeeds");
+
+        outer.resume().await.expect("resume succeeds");
+        assert_eq!(outer.depth, 0);
+    }
+}
@@ -1,13 +1,17 @@
 //! A [`tower_layer::Layer`] that enables the [`Tx`](crate::Tx) extractor.
 
 use std::marker::PhantomData;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
 
 use axum_core::response::IntoResponse;
 use bytes::Bytes;
 use futures_core::future::BoxFuture;
 use http_body::Body;
+use http_body_util::BodyExt;
 
-use crate::extension::Extension;
+use crate::config::{is_retryable, CommitWhen, RetryPolicy, TxOptions};
+use crate::extension::{Extension, TxSource};
 
 /// A [`tower_layer::Layer`] that enables the [`Tx`] extractor.
 ///
@@ -15,33 +19,69 @@ use crate::extension::Extension;
 /// [`Tx`] extractor is used on a request, a connection is acquired from the configured
 /// [`sqlx::Pool`] and a transaction is started on it. The same transaction will be returned for
 /// subsequent uses of [`Tx`] on the same request. The inner service is then called as normal. Once
-/// the inner service responds, the transaction is committed or rolled back depending on the status
-/// code of the response.
+/// the inner service responds, the transaction is committed or rolled back according to the
+/// [`Config::commit_when`](crate::Config::commit_when) policy (by default: commit unless the
+/// response is a 4xx or 5xx).
 ///
 /// [`Tx`]: crate::Tx
 /// [request extensions]: https://docs.rs/http/latest/http/struct.Extensions.html
 pub struct Layer<DB: sqlx::Database, E> {
-    pool: sqlx::Pool<DB>,
+    source: Arc<dyn TxSource<DB>>,
+    nested: bool,
+    commit_when: CommitWhen,
+    tx_options: TxOptions,
+    retry: Option<RetryPolicy>,
+    // Shared across every `Service` this layer produces (and their clones), so concurrent
+    // requests draw distinct salts from the same counter rather than each starting from zero.
+    call_seq: Arc<AtomicU64>,
     _error: PhantomData<E>,
 }
 
+impl<DB: sqlx::Database, E> Layer<DB, E> {
+    pub(crate) fn new(
+        source: Arc<dyn TxSource<DB>>,
+        nested: bool,
+        commit_when: CommitWhen,
+        tx_options: TxOptions,
+        retry: Option<RetryPolicy>,
+    ) -> Self {
+        Self {
+            source,
+            nested,
+            commit_when,
+            tx_options,
+            retry,
+            call_seq: Arc::new(AtomicU64::new(0)),
+            _error: PhantomData,
+        }
+    }
+}
+
 impl<DB: sqlx::Database, E> From<sqlx::Pool<DB>> for Layer<DB, E>
 where
     E: IntoResponse,
     sqlx::Error: Into<E>,
 {
     fn from(value: sqlx::Pool<DB>) -> Self {
-        Self {
-            pool: value,
-            _error: PhantomData,
-        }
+        Self::new(
+            Arc::new(value),
+            false,
+            crate::config::default_commit_when(),
+            TxOptions::default(),
+            None,
+        )
     }
 }
 
 impl<DB: sqlx::Database, E> Clone for Layer<DB, E> {
     fn clone(&self) -> Self {
         Self {
-            pool: self.pool.clone(),
+            source: self.source.clone(),
+            nested: self.nested,
+            commit_when: self.commit_when.clone(),
+            tx_options: self.tx_options,
+            retry: self.retry.clone(),
+            call_seq: self.call_seq.clone(),
             _error: self._error,
         }
     }
@@ -56,7 +96,12 @@ where
 
     fn layer(&self, inner: S) -> Self::Service {
         Service {
-            pool: self.pool.clone(),
+            source: self.source.clone(),
+            nested: self.nested,
+            commit_when: self.commit_when.clone(),
+            tx_options: self.tx_options,
+            retry: self.retry.clone(),
+            call_seq: self.call_seq.clone(),
             inner,
             _error: self._error,
         }
@@ -67,7 +112,12 @@ where
 ///
 /// See [`Layer`] for more information.
 pub struct Service<DB: sqlx::Database, S, E> {
-    pool: sqlx::Pool<DB>,
+    source: Arc<dyn TxSource<DB>>,
+    nested: bool,
+    commit_when: CommitWhen,
+    tx_options: TxOptions,
+    retry: Option<RetryPolicy>,
+    call_seq: Arc<AtomicU64>,
     inner: S,
     _error: PhantomData<E>,
 }
@@ -76,7 +126,12 @@ pub struct Service<DB: sqlx::Database, S, E> {
 impl<DB: sqlx::Database, S: Clone, E> Clone for Service<DB, S, E> {
     fn clone(&self) -> Self {
         Self {
-            pool: self.pool.clone(),
+            source: self.source.clone(),
+            nested: self.nested,
+            commit_when: self.commit_when.clone(),
+            tx_options: self.tx_options,
+            retry: self.retry.clone(),
+            call_seq: self.call_seq.clone(),
             inner: self.inner.clone(),
             _error: self._error,
         }
@@ -91,9 +146,12 @@ where
         Response = http::Response<ResBody>,
         Error = std::convert::Infallible,
     >,
+    S: Clone,
     S::Future: Send + 'static,
     E: IntoResponse,
     sqlx::Error: Into<E>,
+    ReqBody: Body<Data = Bytes> + From<Bytes> + Send + 'static,
+    ReqBody::Error: Into<Box<dyn std::error::Error + Send + Sync + 'static>>,
     ResBody: Body<Data = Bytes> + Send + 'static,
     ResBody::Error: Into<Box<dyn std::error::Error + Send + Sync + 'static>>,
 {
@@ -108,26 +166,133 @@ where
         self.inner.poll_ready(cx).map_err(|err| match err {})
     }
 
-    fn call(&mut self, mut req: http::Request<ReqBody>) -> Self::Future {
-        let ext = Extension::from(self.pool.clone());
-        req.extensions_mut().insert(ext.clone());
+    fn call(&mut self, req: http::Request<ReqBody>) -> Self::Future {
+        let Some(retry) = self.retry.clone() else {
+            let ext = Extension::with_source(self.source.clone(), self.nested, self.tx_options);
+            let commit_when = self.commit_when.clone();
+
+            let mut req = req;
+            req.extensions_mut().insert(ext.clone());
+            let res = self.inner.call(req);
+
+            return Box::pin(async move {
+                let res = res.await.unwrap(); // inner service is infallible
+                Ok(match settle(&ext, &commit_when, res).await {
+                    Outcome::Responded(res) => res,
+                    Outcome::CommitFailed(error) => error.into().into_response(),
+                })
+            });
+        };
 
-        let res = self.inner.call(req);
+        let source = self.source.clone();
+        let nested = self.nested;
+        let tx_options = self.tx_options;
+        let commit_when = self.commit_when.clone();
+        let mut inner = self.inner.clone();
+        // Each call to this (possibly cloned) `Service` draws its own salt from the `Layer`-wide
+        // counter, so concurrently-retrying requests failing at the same attempt number don't all
+        // compute the same jittered delay.
+        let salt = self.call_seq.fetch_add(1, Ordering::Relaxed);
+
+        // A body declared (via `Content-Length`) to already exceed the limit isn't worth
+        // buffering at all: serve it straight through, unbuffered and without retry.
+        let declared_oversized = req
+            .headers()
+            .get(http::header::CONTENT_LENGTH)
+            .and_then(|len| len.to_str().ok())
+            .and_then(|len| len.parse::<u64>().ok())
+            .is_some_and(|len| len > retry.body_limit as u64);
+
+        if declared_oversized {
+            let ext = Extension::with_source(source, nested, tx_options);
+            let mut req = req;
+            req.extensions_mut().insert(ext.clone());
+            let res = inner.call(req);
+
+            return Box::pin(async move {
+                let res = res.await.unwrap(); // inner service is infallible
+                Ok(match settle(&ext, &commit_when, res).await {
+                    Outcome::Responded(res) => res,
+                    Outcome::CommitFailed(error) => error.into().into_response(),
+                })
+            });
+        }
 
         Box::pin(async move {
-            let res = res.await.unwrap(); // inner service is infallible
+            let (parts, body) = req.into_parts();
+            let bytes = match body.collect().await {
+                Ok(collected) => collected.to_bytes(),
+                Err(_) => {
+                    // The body couldn't even be read once; nothing sensible to retry or forward.
+                    return Ok(http::Response::builder()
+                        .status(http::StatusCode::BAD_REQUEST)
+                        .body(axum_core::body::Body::empty())
+                        .expect("status and empty body are always a valid response"));
+                }
+            };
+
+            // A body that turned out to exceed `body_limit` once read (no, or an understated,
+            // `Content-Length`) is still worth serving — it just can't be buffered for a retry,
+            // same as the `declared_oversized` fast path above.
+            let retryable = bytes.len() <= retry.body_limit;
+
+            let mut attempt = 0;
+            loop {
+                let ext = Extension::with_source(source.clone(), nested, tx_options);
+                let req_body = ReqBody::from(bytes.clone());
+                let mut req = http::Request::from_parts(parts.clone(), req_body);
+                req.extensions_mut().insert(ext.clone());
 
-            if !res.status().is_server_error() && !res.status().is_client_error() {
-                if let Err(error) = ext.resolve().await {
-                    return Ok(error.into().into_response());
+                let res = inner.call(req).await.unwrap(); // inner service is infallible
+
+                match settle(&ext, &commit_when, res).await {
+                    Outcome::Responded(res) => return Ok(res),
+                    Outcome::CommitFailed(error) => {
+                        if retryable && attempt < retry.max_attempts && is_retryable(&error) {
+                            attempt += 1;
+                            tokio::time::sleep(retry.delay(attempt, salt)).await;
+                            continue;
+                        }
+                        return Ok(error.into().into_response());
+                    }
                 }
             }
-
-            Ok(res.map(axum_core::body::Body::new))
         })
     }
 }
 
+/// The result of deciding whether to commit or roll back the per-request transaction.
+enum Outcome {
+    /// Either the transaction committed, or the response dictated a rollback; either way the
+    /// response is final.
+    Responded(http::Response<axum_core::body::Body>),
+    /// The final `COMMIT` itself failed; the caller decides whether that's retryable.
+    CommitFailed(sqlx::Error),
+}
+
+/// Commit or roll back the per-request transaction according to `commit_when`.
+async fn settle(
+    ext: &Extension<impl sqlx::Database>,
+    commit_when: &CommitWhen,
+    res: http::Response<impl Body<Data = Bytes> + Send + 'static>,
+) -> Outcome {
+    // Only the head is needed to decide, so a bodyless clone of the parts is passed to
+    // `commit_when` rather than the (possibly unbuffered) body itself.
+    let (parts, body) = res.into_parts();
+    let head = http::Response::from_parts(parts.clone(), ());
+    let res = http::Response::from_parts(parts, body).map(axum_core::body::Body::new);
+
+    if commit_when(&head) {
+        match ext.resolve().await {
+            Ok(()) => Outcome::Responded(res),
+            Err(error) => Outcome::CommitFailed(error),
+        }
+    } else {
+        ext.rollback().await;
+        Outcome::Responded(res)
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use tokio::net::TcpListener;